@@ -1,3 +1,4 @@
+use std::collections::hash_map::Entry;
 use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
 use std::fmt::write;
@@ -5,18 +6,148 @@ use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
 
 use crate::{KvsError, Result};
+use crossbeam_channel::Sender;
+use memmap::Mmap;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
 
 const COMPACTION_THREDHOLD: u64 = 1024 * 1024;
 
-/// The 'KvStore' stores string key/value pairs.
-///
-/// key/value pairs are stored in a 'HashMap' in memory and not persisted to disk.
+/// Size in bytes of the frame header that precedes every serialized
+/// `Command`: a `u32` payload length followed by a `u32` CRC32 of the
+/// payload.
+const FRAME_HEADER_LEN: u64 = 8;
+
+/// Size in bytes of the segment header written at the start of every log
+/// file: a single byte identifying the `Compression` codec used for the
+/// records that follow.
+const SEGMENT_HEADER_LEN: u64 = 1;
+
+/// Default for `LogConfig::max_record_len`.
+const MAX_FRAME_LEN: u64 = 64 * 1024 * 1024;
+
+/// Compression codec applied to a log segment's records.
 ///
-pub struct KvStore {
+/// Chosen per `KvStore::open_with` call (`LogConfig::compression`) and
+/// recorded in each segment's header, so a directory mixing segments
+/// written under different settings still opens correctly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Store records as plain framed bytes (the original format).
+    None,
+    /// Independently zstd-compress each record at the given level.
+    Zstd { level: i32 },
+}
+
+impl Compression {
+    fn tag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd { .. } => 1,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Compression> {
+        match tag {
+            0 => Ok(Compression::None),
+            // the compression level only matters for the encoder; zstd's
+            // frame format is self-describing on decode.
+            1 => Ok(Compression::Zstd { level: 0 }),
+            _ => Err(KvsError::CorruptLog),
+        }
+    }
+}
+
+/// Settings for a log: codec, reader strategy, and when to roll or compact
+/// segments. Part of `KvStoreConfig`, passed to `KvStore::open_with`.
+#[derive(Debug, Clone, Copy)]
+pub struct LogConfig {
+    /// Codec used for new segments (the active log, and compaction/roll
+    /// output). Existing segments keep whatever codec they were written
+    /// with.
+    pub compression: Compression,
+
+    /// Resolve immutable segments through a read-only `Mmap` instead of a
+    /// `BufReaderWithPos`. See `get`.
+    pub use_mmap: bool,
+
+    /// Request a background compaction once this many stale bytes have
+    /// accumulated in the index.
+    pub compaction_threshold: u64,
+
+    /// Roll the active log to a new generation once it grows past this
+    /// many bytes, independent of compaction.
+    pub max_segment_size: u64,
+
+    /// Call `File::sync_data` after each `set`/`remove` flushes its record,
+    /// trading write throughput for durability against a crash. Compaction
+    /// output is flushed but not synced.
+    pub sync_on_write: bool,
+
+    /// Sanity cap on a single record's on-disk length, checked while
+    /// replaying a log generation at `open` — before a record's CRC can be
+    /// trusted, so a single bit-flip in its length field can't demand an
+    /// arbitrarily large allocation. Only applied to that initial,
+    /// not-yet-verified scan: once a record's position is in the index
+    /// (written by this process, or already scanned past this check at a
+    /// prior `open`), later reads of it trust the length regardless of
+    /// size. Raise this if legitimate records exceed the default.
+    pub max_record_len: u64,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            compression: Compression::None,
+            use_mmap: false,
+            compaction_threshold: COMPACTION_THREDHOLD,
+            max_segment_size: u64::MAX,
+            sync_on_write: false,
+            max_record_len: MAX_FRAME_LEN,
+        }
+    }
+}
+
+/// Open-time settings for a `KvStore`, passed to `KvStore::open_with`.
+/// `KvStore::open` is a thin wrapper around `open_with(path,
+/// KvStoreConfig::default())`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KvStoreConfig {
+    /// Skip `create_dir_all` and all file creation, keeping every segment
+    /// in memory instead. Meant for tests that don't want to touch the
+    /// filesystem.
+    pub in_memory: bool,
+
+    /// Settings for the log itself.
+    pub log_config: LogConfig,
+}
+
+/// A log segment's reader handle: either a buffered file handle (for the
+/// active segment, which is still being appended to and so can't be mapped
+/// stably) or a read-only memory map (for an immutable segment, once it's
+/// been looked up after it stopped growing).
+enum SegmentReader {
+    Buffered(BufReaderWithPos<SegmentIo>),
+    Mapped(Mmap),
+}
+
+/// Message sent to the compaction worker thread.
+enum WorkerMessage {
+    /// Rewrite live entries into a fresh generation and retire stale ones.
+    Compact,
+    /// Stop the worker. Sent from `KvStore`'s `Drop` impl.
+    Shutdown,
+}
+
+/// State touched by both the foreground `KvStore` methods and the
+/// background compaction worker. Guarded by a single mutex so the worker
+/// can atomically swap in a freshly compacted generation while `get`/`set`
+/// keep working against the current one in the meantime.
+struct Shared {
     /// directory for the log and other data.
     path: PathBuf,
 
@@ -24,76 +155,233 @@ pub struct KvStore {
     current_gen: u64,
 
     /// immutable files handle that may be contain stale data
-    readers: HashMap<u64, BufReaderWithPos<File>>,
+    readers: HashMap<u64, SegmentReader>,
 
     /// active file handle that can be writen and read
-    writer: BufWriterWithPos<File>,
+    writer: BufWriterWithPos<SegmentIo>,
 
     /// store in-memory index for quickly search log position in log file
     index: BTreeMap<String, CommandPos>,
 
     /// when entry that stale more than `canbe_compacted`, then trigger compaction
     canbe_compacted: u64,
+
+    /// codec each open generation's segment was written with, read back
+    /// from its segment header
+    codecs: HashMap<u64, Compression>,
+
+    /// codec used for segments created from this point on (new active log,
+    /// compaction output)
+    compression: Compression,
+
+    /// when set, `get` remaps an immutable segment's reader to a read-only
+    /// `Mmap` the first time it's looked up, instead of seeking through a
+    /// `BufReaderWithPos`.
+    use_mmap: bool,
+
+    /// backing buffers for in-memory segments, keyed by gen. Only
+    /// populated (and consulted) when `in_memory` is set.
+    mem_segments: HashMap<u64, Arc<Mutex<Vec<u8>>>>,
+
+    /// when set, segments live only in `mem_segments`; nothing touches the
+    /// filesystem.
+    in_memory: bool,
+
+    /// `LogConfig::compaction_threshold` this store was opened with.
+    compaction_threshold: u64,
+
+    /// `LogConfig::max_segment_size` this store was opened with.
+    max_segment_size: u64,
+
+    /// `LogConfig::sync_on_write` this store was opened with.
+    sync_on_write: bool,
+}
+
+/// The 'KvStore' stores string key/value pairs.
+///
+/// key/value pairs are appended as framed records to log segment files on
+/// disk (or to in-memory segments when `KvStoreConfig::in_memory` is set),
+/// with an in-memory `BTreeMap` index from each key to its latest record.
+///
+pub struct KvStore {
+    shared: Arc<Mutex<Shared>>,
+
+    /// sends compaction requests to `worker`; `set` uses this instead of
+    /// compacting inline so the caller never stalls on a rewrite.
+    worker_tx: Sender<WorkerMessage>,
+
+    /// set while a compaction request is in flight, so repeated threshold
+    /// trips before the worker gets to it coalesce into a single rewrite.
+    compact_pending: Arc<AtomicBool>,
+
+    /// the compaction worker thread, joined on drop so a pending compaction
+    /// is never lost when the store is closed.
+    worker: Option<JoinHandle<()>>,
 }
 
 impl KvStore {
     /// Open the KvStore at a given path. return the KvStore
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreConfig::default())
+    }
+
+    /// Open the KvStore at a given path with explicit `config`.
+    pub fn open_with(path: impl Into<PathBuf>, config: KvStoreConfig) -> Result<KvStore> {
         let path = path.into();
-        fs::create_dir_all(&path)?;
+        let KvStoreConfig {
+            in_memory,
+            log_config:
+                LogConfig {
+                    compression,
+                    use_mmap,
+                    compaction_threshold,
+                    max_segment_size,
+                    sync_on_write,
+                    max_record_len,
+                },
+        } = config;
 
-        let mut index = BTreeMap::new();
+        if !in_memory {
+            fs::create_dir_all(&path)?;
+        }
+
+        // a snapshot, if present, gives us the index as of the last
+        // compaction plus the highest gen it covers, so we only have to
+        // replay log generations newer than that instead of the whole
+        // history. a missing or unreadable snapshot just means start cold.
+        // `in_memory` stores never have one to load.
+        let (mut index, watermark) = if in_memory {
+            (BTreeMap::new(), 0)
+        } else {
+            load_index_snapshot(&path).unwrap_or_else(|| (BTreeMap::new(), 0))
+        };
 
         // immutable file only can be read
         let mut readers = HashMap::new();
+        let mut codecs = HashMap::new();
+        let mut mem_segments = HashMap::new();
 
         let canbe_compacted: u64 = 0;
 
-        let gen_list = sorted_gen_list(&path)?;
+        // `in_memory` stores start with nothing to replay: there is no
+        // directory to scan for prior generations.
+        let gen_list = if in_memory { Vec::new() } else { sorted_gen_list(&path)? };
 
         for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            build_index_from_log(gen, &mut reader, &mut index)?;
-            readers.insert(gen, reader);
+            let codec = Compression::from_tag(read_segment_header(&log_path(&path, gen))?)?;
+            codecs.insert(gen, codec);
+
+            let mut reader = BufReaderWithPos::new(SegmentIo::Disk(File::open(log_path(&path, gen))?))?;
+            reader.seek(SeekFrom::Start(SEGMENT_HEADER_LEN))?;
+
+            if gen > watermark {
+                let scan = build_index_from_log(gen, &mut reader, codec, &mut index, max_record_len)?;
+
+                if let Some(torn_at) = scan.truncate_to {
+                    // a partially written final record: drop it and truncate the
+                    // file back to the last good frame boundary so future
+                    // appends start from clean ground and `open` still succeeds.
+                    OpenOptions::new().write(true).open(log_path(&path, gen))?.set_len(torn_at)?;
+                    reader = BufReaderWithPos::new(SegmentIo::Disk(File::open(log_path(&path, gen))?))?;
+                    reader.seek(SeekFrom::End(0))?;
+                }
+            }
+
+            readers.insert(gen, SegmentReader::Buffered(reader));
         }
 
         // only one active file can be writen.
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
 
-        let writer = create_active_log_file(&path, current_gen, &mut readers)?;
+        let writer = create_active_log_file(
+            &path,
+            current_gen,
+            &mut readers,
+            &mut codecs,
+            compression,
+            in_memory,
+            &mut mem_segments,
+        )?;
 
-        Ok(KvStore {
+        let shared = Arc::new(Mutex::new(Shared {
             path,
             current_gen,
             readers,
             writer,
             index,
             canbe_compacted,
+            codecs,
+            compression,
+            use_mmap,
+            mem_segments,
+            in_memory,
+            compaction_threshold,
+            max_segment_size,
+            sync_on_write,
+        }));
+
+        let (worker_tx, worker_rx) = crossbeam_channel::unbounded();
+        let compact_pending = Arc::new(AtomicBool::new(false));
+
+        let worker = {
+            let shared = Arc::clone(&shared);
+            let compact_pending = Arc::clone(&compact_pending);
+            std::thread::spawn(move || {
+                for msg in worker_rx {
+                    match msg {
+                        WorkerMessage::Compact => {
+                            // best-effort: a failed compaction just means the
+                            // log keeps growing until the next successful one.
+                            let _ = run_compaction(&shared);
+                            compact_pending.store(false, Ordering::SeqCst);
+                        }
+                        WorkerMessage::Shutdown => break,
+                    }
+                }
+            })
+        };
+
+        Ok(KvStore {
+            shared,
+            worker_tx,
+            compact_pending,
+            worker: Some(worker),
         })
     }
 
     /// Sets the value of a string key to a sting
     /// Return an error if the value is not written successfully
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+
         let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
+        let pos = shared.writer.pos;
+        let compression = shared.compression;
+        write_record(&mut shared.writer, &cmd, compression)?;
+        shared.writer.flush()?;
+        if shared.sync_on_write {
+            shared.writer.sync_data()?;
+        }
 
         if let Command::Set {
             key,
             ..
         } = cmd
         {
-            if let Some(old_cmd) =
-                self.index.insert(key, (self.current_gen, pos..self.writer.pos).into())
-            {
-                self.canbe_compacted += old_cmd.len;
+            let current_gen = shared.current_gen;
+            let end_pos = shared.writer.pos;
+            if let Some(old_cmd) = shared.index.insert(key, (current_gen, pos..end_pos).into()) {
+                shared.canbe_compacted += old_cmd.len;
             }
         }
 
-        if self.canbe_compacted > COMPACTION_THREDHOLD {
-            self.compact()?;
+        shared.roll_if_needed()?;
+
+        let should_compact = shared.canbe_compacted > shared.compaction_threshold;
+        drop(shared);
+
+        if should_compact {
+            self.request_compaction();
         }
 
         Ok(())
@@ -103,17 +391,24 @@ impl KvStore {
     /// if the key does not exist, return `None`.
     /// Return an error if the value is not read successfully
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            let reader = self.readers.get_mut(&cmd_pos.gen).expect("Cannot find log msg");
+        let mut shared = self.shared.lock().unwrap();
 
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        if let Some(cmd_pos) = shared.index.get(&key).copied() {
+            let codec = *shared.codecs.get(&cmd_pos.gen).unwrap_or(&Compression::None);
+
+            if shared.use_mmap {
+                let active_gen = shared.current_gen;
+                let path = shared.path.clone();
+                let in_memory = shared.in_memory;
+                remap_if_immutable(&mut shared.readers, &path, cmd_pos.gen, active_gen, in_memory)?;
+            }
 
-            let cmd_reader = reader.take(cmd_pos.len);
+            let cmd = read_segment_record(&mut shared.readers, cmd_pos.gen, cmd_pos.pos, cmd_pos.len, codec)?;
 
             if let Command::Set {
                 value,
                 ..
-            } = serde_json::from_reader(cmd_reader)?
+            } = cmd
             {
                 Ok(Some(value))
             } else {
@@ -127,78 +422,350 @@ impl KvStore {
     /// Remove a given key.
     /// Return an error if the key does not exist or is not removed successfully
     pub fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
+        let mut shared = self.shared.lock().unwrap();
+
+        if shared.index.contains_key(&key) {
             let cmd = Command::remove(key);
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
+            let compression = shared.compression;
+            write_record(&mut shared.writer, &cmd, compression)?;
+            shared.writer.flush()?;
+            if shared.sync_on_write {
+                shared.writer.sync_data()?;
+            }
             if let Command::Remove {
                 key,
             } = cmd
             {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.canbe_compacted += old_cmd.len;
+                let old_cmd = shared.index.remove(&key).expect("key not found");
+                shared.canbe_compacted += old_cmd.len;
             }
+            shared.roll_if_needed()?;
             Ok(())
         } else {
             Err(KvsError::KeyNotFound)
         }
     }
 
+    /// Ask the compaction worker to rewrite the log, coalescing with any
+    /// request already in flight instead of piling up duplicates.
+    fn request_compaction(&self) {
+        if !self.compact_pending.swap(true, Ordering::SeqCst) {
+            // the send only fails if the worker thread has already exited
+            // (e.g. it panicked); there's nothing useful to do with a
+            // failed send.
+            let _ = self.worker_tx.send(WorkerMessage::Compact);
+        }
+    }
+}
 
-    fn compact(&mut self) -> Result<()> {
-        // increase current gen by 2. current_gen + 1 is for the compaction file.
-        let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = create_active_log_file(&self.path, self.current_gen, &mut self.readers)?;
-        
-        let mut compaction_writer = create_active_log_file(&self.path, compaction_gen, &mut self.readers)?;
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        let _ = self.worker_tx.send(WorkerMessage::Shutdown);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
 
-        let mut new_pos = 0;
+/// Point-in-time state `run_compaction` needs to rewrite the log without
+/// holding `Shared`'s lock for the rewrite itself.
+struct CompactionPlan {
+    compaction_gen: u64,
+    path: PathBuf,
+    codecs: HashMap<u64, Compression>,
+    compression: Compression,
+    in_memory: bool,
+    mem_segments: HashMap<u64, Arc<Mutex<Vec<u8>>>>,
+    index: BTreeMap<String, CommandPos>,
+    canbe_compacted_baseline: u64,
+}
 
-        // since entry in memory index is latest data, so if meta information of entry in read file is equal to index 
-        // then the entry is latest, we insert the entry into compaction file
-        for cmd_pos in &mut self.index.values_mut() {
-            let reader = self.readers.get_mut(&cmd_pos.gen).expect("Cannot find log reader");
+/// Rewrite every live index entry into a fresh compaction segment and swap
+/// it in, taking `shared`'s lock only for the two short critical sections
+/// that bracket the rewrite: reserving generations up front, and merging
+/// the result back in afterwards. `get`/`set` keep running against the old
+/// readers and the newly reserved active segment for the whole rewrite in
+/// between, instead of stalling for it.
+fn run_compaction(shared: &Arc<Mutex<Shared>>) -> Result<()> {
+    // increase current gen by 2. current_gen + 1 is for the compaction file.
+    // Bumping `current_gen` here (not after the rewrite) is what lets `set`
+    // keep appending to a fresh active segment while the rewrite below runs
+    // unlocked.
+    let plan = {
+        let mut guard = shared.lock().unwrap();
 
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            }
+        // `MutexGuard<Shared>` doesn't let the borrow checker see disjoint
+        // fields through its `DerefMut` the way a plain `&mut Shared`
+        // would, so reborrow through it once up front — same trick `get`
+        // uses (there via locals) around `remap_if_immutable` — rather than
+        // borrowing multiple fields off `guard` directly.
+        let shared: &mut Shared = &mut guard;
 
-            let mut entry_reader = reader.take(cmd_pos.len);
-            let len = io::copy(&mut entry_reader, &mut compaction_writer)?;
-            *cmd_pos = (compaction_gen, new_pos..new_pos+len).into();
-            new_pos += len;
+        let compaction_gen = shared.current_gen + 1;
+        shared.current_gen += 2;
+        let path = shared.path.clone();
+        let next_gen = shared.current_gen;
+        let compression = shared.compression;
+        let in_memory = shared.in_memory;
+        shared.writer = create_active_log_file(
+            &path,
+            next_gen,
+            &mut shared.readers,
+            &mut shared.codecs,
+            compression,
+            in_memory,
+            &mut shared.mem_segments,
+        )?;
+
+        CompactionPlan {
+            compaction_gen,
+            path: shared.path.clone(),
+            codecs: shared.codecs.clone(),
+            compression: shared.compression,
+            in_memory: shared.in_memory,
+            mem_segments: shared.mem_segments.clone(),
+            index: shared.index.clone(),
+            canbe_compacted_baseline: shared.canbe_compacted,
         }
-        compaction_writer.flush()?;
+    };
+
+    // Rewrite every entry that was live as of the snapshot above, through
+    // private reader/writer handles that never touch `shared`. Old and new
+    // segments may use different codecs, so each record is decoded under
+    // its own gen's codec and re-encoded under `plan.compression` rather
+    // than copied byte-for-byte.
+    let mut mem_segments = plan.mem_segments;
+    let mut compaction_writer = {
+        let mut io = open_segment_writer(&plan.path, plan.compaction_gen, &mut mem_segments, plan.in_memory)?;
+        io.write_all(&[plan.compression.tag()])?;
+        BufWriterWithPos::new(io)?
+    };
 
-        // remove stale log file
-        let stale_gens: Vec<_> = self.readers.keys().filter(|&&gen| gen < compaction_gen).cloned().collect();
+    let mut readers: HashMap<u64, SegmentReader> = HashMap::new();
+    let mut new_positions: BTreeMap<String, CommandPos> = BTreeMap::new();
 
-        for stale_gen in stale_gens {
-            self.readers.remove(&stale_gen);
-            fs::remove_file(log_path(&self.path, stale_gen))?;
+    for (key, cmd_pos) in &plan.index {
+        if let Entry::Vacant(entry) = readers.entry(cmd_pos.gen) {
+            let mut reader = BufReaderWithPos::new(open_segment_reader(&plan.path, cmd_pos.gen, &mut mem_segments, plan.in_memory)?)?;
+            reader.seek(SeekFrom::Start(SEGMENT_HEADER_LEN))?;
+            entry.insert(SegmentReader::Buffered(reader));
         }
+        let codec = *plan.codecs.get(&cmd_pos.gen).unwrap_or(&Compression::None);
+        let cmd = read_segment_record(&mut readers, cmd_pos.gen, cmd_pos.pos, cmd_pos.len, codec)?;
+        let new_pos = compaction_writer.pos;
+        write_record(&mut compaction_writer, &cmd, plan.compression)?;
+        new_positions.insert(key.clone(), (plan.compaction_gen, new_pos..compaction_writer.pos).into());
+    }
+    compaction_writer.flush()?;
+    drop(compaction_writer);
+    drop(readers);
 
-        self.canbe_compacted = 0;
-        Ok(())
+    // Merge the rewrite back in and retire the gens it replaced.
+    let mut shared = shared.lock().unwrap();
 
+    // since entry in memory index is latest data, so if meta information of
+    // entry in read file is equal to index then the entry is still the one
+    // we just rewrote; if `set`/`remove` touched it while the rewrite above
+    // was running unlocked, leave it pointing wherever that left it instead
+    // of clobbering a newer write with our stale copy.
+    for (key, new_pos) in &new_positions {
+        let rewrote_from = plan.index.get(key).expect("new_positions keys come from plan.index");
+        if matches!(shared.index.get(key), Some(current) if current == rewrote_from) {
+            shared.index.insert(key.clone(), *new_pos);
+        }
     }
 
+    if plan.in_memory {
+        if let Some(buf) = mem_segments.remove(&plan.compaction_gen) {
+            shared.mem_segments.insert(plan.compaction_gen, buf);
+        }
+    }
+
+    let path = shared.path.clone();
+    let in_memory = shared.in_memory;
+    let mut reader = BufReaderWithPos::new(open_segment_reader(&path, plan.compaction_gen, &mut shared.mem_segments, in_memory)?)?;
+    reader.seek(SeekFrom::Start(SEGMENT_HEADER_LEN))?;
+    shared.readers.insert(plan.compaction_gen, SegmentReader::Buffered(reader));
+    shared.codecs.insert(plan.compaction_gen, plan.compression);
+
+    // the compaction file fully reflects every entry it rewrote, so it's
+    // safe to snapshot the index with `compaction_gen` as the watermark: a
+    // later `open` can skip replaying it and everything before it.
+    // `in_memory` stores have nowhere to write one.
+    //
+    // This must happen *before* the stale files below are removed: the
+    // snapshot write is atomic (temp file + rename), so a crash here either
+    // leaves the old snapshot in place (safe: `open` replays the
+    // still-present stale gens) or lands the new snapshot before any file
+    // is gone. Deleting first would let a crash between the deletes and the
+    // snapshot write leave the *old* snapshot pointing at gens that no
+    // longer exist, panicking the next `open`'s readers.
+    if !shared.in_memory {
+        write_index_snapshot(&shared.path, &shared.index, plan.compaction_gen)?;
+    }
+
+    // remove stale log file
+    let stale_gens: Vec<_> = shared.readers.keys().filter(|&&gen| gen < plan.compaction_gen).cloned().collect();
+
+    for stale_gen in stale_gens {
+        shared.readers.remove(&stale_gen);
+        shared.codecs.remove(&stale_gen);
+        if shared.in_memory {
+            shared.mem_segments.remove(&stale_gen);
+        } else {
+            fs::remove_file(log_path(&shared.path, stale_gen))?;
+        }
+    }
+
+    // only the staleness counted as of the snapshot above was addressed by
+    // this rewrite; anything accrued from concurrent writes since then is
+    // still owed to the next compaction.
+    shared.canbe_compacted = shared.canbe_compacted.saturating_sub(plan.canbe_compacted_baseline);
+
+    Ok(())
+}
+
+impl Shared {
+    /// Roll the active log to a new generation once it grows past
+    /// `max_segment_size`, independent of compaction.
+    fn roll_if_needed(&mut self) -> Result<()> {
+        if self.writer.pos <= self.max_segment_size {
+            return Ok(());
+        }
+
+        self.current_gen += 1;
+        self.writer = create_active_log_file(
+            &self.path,
+            self.current_gen,
+            &mut self.readers,
+            &mut self.codecs,
+            self.compression,
+            self.in_memory,
+            &mut self.mem_segments,
+        )?;
+
+        Ok(())
+    }
 }
 
 fn create_active_log_file(
     path: &Path,
     gen: u64,
-    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
-) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new().create(true).write(true).append(true).open(&path)?,
-    )?;
-
-    readers.insert(gen, BufReaderWithPos::new(File::open(&path)?)?);
+    readers: &mut HashMap<u64, SegmentReader>,
+    codecs: &mut HashMap<u64, Compression>,
+    compression: Compression,
+    in_memory: bool,
+    mem_segments: &mut HashMap<u64, Arc<Mutex<Vec<u8>>>>,
+) -> Result<BufWriterWithPos<SegmentIo>> {
+    let mut io = open_segment_writer(path, gen, mem_segments, in_memory)?;
+    io.write_all(&[compression.tag()])?;
+    let writer = BufWriterWithPos::new(io)?;
+
+    let mut reader = BufReaderWithPos::new(open_segment_reader(path, gen, mem_segments, in_memory)?)?;
+    reader.seek(SeekFrom::Start(SEGMENT_HEADER_LEN))?;
+    readers.insert(gen, SegmentReader::Buffered(reader));
+    codecs.insert(gen, compression);
+
     Ok(writer)
 }
+
+/// Open a writable handle onto `gen`'s segment: a real file on disk
+/// (created if missing, appended to otherwise), or a fresh cursor over its
+/// shared in-memory buffer (created empty if this is the first cursor onto
+/// that gen).
+fn open_segment_writer(
+    path: &Path,
+    gen: u64,
+    mem_segments: &mut HashMap<u64, Arc<Mutex<Vec<u8>>>>,
+    in_memory: bool,
+) -> Result<SegmentIo> {
+    if in_memory {
+        let buf = mem_segments.entry(gen).or_insert_with(|| Arc::new(Mutex::new(Vec::new())));
+        Ok(SegmentIo::Memory(MemFile {
+            data: Arc::clone(buf),
+            pos: 0,
+        }))
+    } else {
+        Ok(SegmentIo::Disk(OpenOptions::new().create(true).append(true).open(log_path(path, gen))?))
+    }
+}
+
+/// Open a read-only handle onto `gen`'s segment, mirroring
+/// `open_segment_writer` for the in-memory case (same shared buffer, a
+/// fresh cursor).
+fn open_segment_reader(
+    path: &Path,
+    gen: u64,
+    mem_segments: &mut HashMap<u64, Arc<Mutex<Vec<u8>>>>,
+    in_memory: bool,
+) -> Result<SegmentIo> {
+    if in_memory {
+        let buf = mem_segments.entry(gen).or_insert_with(|| Arc::new(Mutex::new(Vec::new())));
+        Ok(SegmentIo::Memory(MemFile {
+            data: Arc::clone(buf),
+            pos: 0,
+        }))
+    } else {
+        Ok(SegmentIo::Disk(File::open(log_path(path, gen))?))
+    }
+}
+
+/// Read the record at `(gen, pos, len)`, decoding under `compression`,
+/// through whichever reader `gen` currently has: seek-and-read through a
+/// `BufReaderWithPos`, or a zero-copy slice of an `Mmap`.
+fn read_segment_record(
+    readers: &mut HashMap<u64, SegmentReader>,
+    gen: u64,
+    pos: u64,
+    len: u64,
+    compression: Compression,
+) -> Result<Command> {
+    match readers.get_mut(&gen).expect("Cannot find log reader") {
+        SegmentReader::Mapped(mmap) => {
+            let start = pos as usize;
+            let end = start + len as usize;
+            let slice = mmap.get(start..end).ok_or(KvsError::CorruptLog)?;
+            read_record(&mut &slice[..], len, compression)
+        }
+        SegmentReader::Buffered(reader) => {
+            reader.seek(SeekFrom::Start(pos))?;
+            read_record(reader, len, compression)
+        }
+    }
+}
+
+/// Remap `gen`'s reader from a buffered file handle to a read-only `Mmap`
+/// the first time it's looked up after becoming immutable, i.e. once it's
+/// no longer the segment `set` is still appending to. The still-growing
+/// active segment is left as `Buffered`, since it can't be mapped stably.
+fn remap_if_immutable(
+    readers: &mut HashMap<u64, SegmentReader>,
+    path: &Path,
+    gen: u64,
+    active_gen: u64,
+    in_memory: bool,
+) -> Result<()> {
+    // in-memory segments have no file to map; they stay `Buffered` for
+    // their whole life.
+    if in_memory || gen == active_gen {
+        return Ok(());
+    }
+
+    if matches!(readers.get(&gen), Some(SegmentReader::Buffered(_))) {
+        let mmap = unsafe { Mmap::map(&File::open(log_path(path, gen))?)? };
+        readers.insert(gen, SegmentReader::Mapped(mmap));
+    }
+
+    Ok(())
+}
+
+/// Read the codec tag from a segment's header.
+fn read_segment_header(path: &Path) -> Result<u8> {
+    let mut file = File::open(path)?;
+    let mut tag = [0u8; SEGMENT_HEADER_LEN as usize];
+    file.read_exact(&mut tag)?;
+    Ok(tag[0])
+}
 /// Return sorted generation numbers in the given directory.
 fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     let mut gen_list: Vec<u64> = fs::read_dir(&path)?
@@ -222,40 +789,251 @@ fn log_path(path: &Path, gen: u64) -> PathBuf {
     path.join(format!("{}.log", gen))
 }
 
+fn snapshot_path(path: &Path) -> PathBuf {
+    path.join("index.snapshot")
+}
+
+/// On-disk form of an index snapshot: the index as it stood right after a
+/// compaction, plus the highest log generation it already accounts for.
+#[derive(Serialize, Deserialize)]
+struct IndexSnapshot {
+    watermark: u64,
+    index: BTreeMap<String, CommandPos>,
+}
+
+/// Load the index snapshot for `path`, if one exists and is readable.
+/// Any failure to read or deserialize it is treated as "no snapshot" so
+/// `open` falls back to a full log replay rather than failing to start.
+fn load_index_snapshot(path: &Path) -> Option<(BTreeMap<String, CommandPos>, u64)> {
+    let file = File::open(snapshot_path(path)).ok()?;
+    let snapshot: IndexSnapshot = serde_json::from_reader(file).ok()?;
+    Some((snapshot.index, snapshot.watermark))
+}
+
+/// Persist `index` as a snapshot covering every generation up to and
+/// including `watermark`. Written to a temp file and renamed into place
+/// so a crash mid-write never leaves a half-written snapshot behind.
+fn write_index_snapshot(path: &Path, index: &BTreeMap<String, CommandPos>, watermark: u64) -> Result<()> {
+    let snapshot = IndexSnapshot {
+        watermark,
+        index: index.clone(),
+    };
+
+    let tmp_path = path.join("index.snapshot.tmp");
+    serde_json::to_writer(File::create(&tmp_path)?, &snapshot)?;
+    fs::rename(&tmp_path, snapshot_path(path))?;
+    Ok(())
+}
+
+/// Outcome of replaying a single log generation.
+struct LogScan {
+    /// Set when the log's last record was only partially written (a crash
+    /// mid-`flush`). Holds the offset of the last good frame boundary that
+    /// the file should be truncated back to.
+    truncate_to: Option<u64>,
+}
+
 /// build index from log file
 fn build_index_from_log(
     gen: u64,
-    reader: &mut BufReaderWithPos<File>,
+    reader: &mut BufReaderWithPos<SegmentIo>,
+    compression: Compression,
     index: &mut BTreeMap<String, CommandPos>,
-) -> Result<u64> {
-
-    let mut canbe_compacted: u64 = 0;
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    while let Some(cmd) = stream.next() {
-        let end_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set {
-                key,
-                ..
-            } => {
-                if let Some(old_cmd) = index.insert(key, (gen, pos..end_pos).into()) {
-                    canbe_compacted += old_cmd.len;            
+    max_record_len: u64,
+) -> Result<LogScan> {
+    let mut pos = reader.seek(SeekFrom::Start(SEGMENT_HEADER_LEN))?;
+    let mut truncate_to = None;
+
+    loop {
+        match try_read_record(reader, compression, max_record_len)? {
+            FrameRead::Complete(cmd) => {
+                let end_pos = reader.pos;
+                match cmd {
+                    Command::Set {
+                        key,
+                        ..
+                    } => {
+                        index.insert(key, (gen, pos..end_pos).into());
+                    }
+                    Command::Remove {
+                        key,
+                    } => {
+                        index.remove(&key);
+                    }
                 }
+                pos = end_pos;
             }
-            Command::Remove {
-                key,
-            } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    canbe_compacted += old_cmd.len;
+            FrameRead::Eof => break,
+            FrameRead::TornTail => {
+                truncate_to = Some(pos);
+                break;
+            }
+            FrameRead::CrcMismatch => return Err(KvsError::CorruptLog),
+        }
+    }
+
+    Ok(LogScan { truncate_to })
+}
+
+/// Result of attempting to read one frame from a log.
+enum FrameRead {
+    /// A full, checksum-verified command.
+    Complete(Command),
+    /// Clean end of stream: no more frames follow.
+    Eof,
+    /// The frame header or payload was cut short, or its CRC didn't match
+    /// and nothing follows it in the file. Either way this is a torn tail
+    /// left behind by a crash mid-write, not real corruption.
+    TornTail,
+    /// The CRC didn't match but more data follows in the file, so this
+    /// can't be explained by a torn tail: the log is corrupt. Also returned,
+    /// without a CRC check, when a frame's declared length exceeds the cap.
+    CrcMismatch,
+}
+
+/// Write `cmd` to `writer` as one on-disk record under `compression`.
+///
+/// Uncompressed records are the frame bytes as-is. Compressed records
+/// independently zstd-compress the frame and store it as a block (its own
+/// `u32` length prefix followed by the compressed bytes), since a zstd
+/// stream can't be seeked into the way `CommandPos` expects.
+fn write_record<W: Write>(writer: &mut W, cmd: &Command, compression: Compression) -> Result<()> {
+    let frame = encode_frame(cmd)?;
+    match compression {
+        Compression::None => writer.write_all(&frame)?,
+        Compression::Zstd { level } => {
+            let block = zstd::stream::encode_all(&frame[..], level)?;
+            writer.write_all(&(block.len() as u32).to_le_bytes())?;
+            writer.write_all(&block)?;
+        }
+    }
+    Ok(())
+}
+
+/// Serialize `cmd` into frame bytes: `[len: u32][crc32: u32][payload]`.
+fn encode_frame(cmd: &Command) -> Result<Vec<u8>> {
+    let payload = serde_json::to_vec(cmd)?;
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN as usize + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32(&payload).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Read exactly one on-disk record (`len` bytes) written under `compression`
+/// and checksum-verify it. Intended for call sites (like `get`) that only
+/// ever read records the index already verified as complete; any
+/// discrepancy is reported as `KvsError::CorruptLog`. `len` is trusted as-is
+/// (no `max_record_len` cap): it's either a position this process just
+/// wrote, or one a prior `open`'s scan already checked.
+fn read_record<R: Read>(reader: &mut R, len: u64, compression: Compression) -> Result<Command> {
+    match try_read_record(&mut reader.take(len), compression, u64::MAX)? {
+        FrameRead::Complete(cmd) => Ok(cmd),
+        FrameRead::Eof | FrameRead::TornTail | FrameRead::CrcMismatch => Err(KvsError::CorruptLog),
+    }
+}
+
+/// Attempt to read the next on-disk record written under `compression`,
+/// distinguishing a torn write from real corruption the same way
+/// `try_read_frame` does for the uncompressed case. `max_len` caps the
+/// declared length (payload or, here, compressed block) before it's used
+/// to size an allocation — see `LogConfig::max_record_len`.
+fn try_read_record<R: Read>(reader: &mut R, compression: Compression, max_len: u64) -> Result<FrameRead> {
+    match compression {
+        Compression::None => try_read_frame(reader, max_len),
+        Compression::Zstd { .. } => {
+            let mut header = [0u8; 4];
+            let n = read_to_end_or_limit(reader, &mut header)?;
+            if n == 0 {
+                return Ok(FrameRead::Eof);
+            }
+            if n < header.len() {
+                return Ok(FrameRead::TornTail);
+            }
+
+            let block_len = u32::from_le_bytes(header) as usize;
+            if block_len as u64 > max_len {
+                return Ok(FrameRead::CrcMismatch);
+            }
+            let mut block = vec![0u8; block_len];
+            let n = read_to_end_or_limit(reader, &mut block)?;
+            if n < block.len() {
+                return Ok(FrameRead::TornTail);
+            }
+
+            match zstd::stream::decode_all(&block[..]) {
+                Ok(frame) => try_read_frame(&mut &frame[..], max_len),
+                Err(_) => {
+                    let mut probe = [0u8; 1];
+                    Ok(if read_to_end_or_limit(reader, &mut probe)? == 0 {
+                        FrameRead::TornTail
+                    } else {
+                        FrameRead::CrcMismatch
+                    })
                 }
-                // the `remove` command itself can be deleted in the next compaction.
-                canbe_compacted += end_pos - pos;
             }
         }
-        pos = end_pos;
     }
-    Ok(canbe_compacted)
+}
+
+/// `max_len` caps the frame's declared payload length before it's used to
+/// size an allocation — see `LogConfig::max_record_len`. Pass `u64::MAX`
+/// for an already-trusted length (nothing to cap).
+fn try_read_frame<R: Read>(reader: &mut R, max_len: u64) -> Result<FrameRead> {
+    let mut header = [0u8; FRAME_HEADER_LEN as usize];
+    let n = read_to_end_or_limit(reader, &mut header)?;
+    if n == 0 {
+        return Ok(FrameRead::Eof);
+    }
+    if n < header.len() {
+        return Ok(FrameRead::TornTail);
+    }
+
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let expected_crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+
+    if len as u64 > max_len {
+        return Ok(FrameRead::CrcMismatch);
+    }
+
+    let mut payload = vec![0u8; len];
+    let n = read_to_end_or_limit(reader, &mut payload)?;
+    if n < payload.len() {
+        return Ok(FrameRead::TornTail);
+    }
+
+    if crc32(&payload) != expected_crc {
+        // a mismatch right at the tail of the file (nothing left to read
+        // afterwards) is indistinguishable from a torn write; anywhere else
+        // it's real corruption.
+        let mut probe = [0u8; 1];
+        return Ok(if read_to_end_or_limit(reader, &mut probe)? == 0 {
+            FrameRead::TornTail
+        } else {
+            FrameRead::CrcMismatch
+        });
+    }
+
+    Ok(FrameRead::Complete(serde_json::from_slice(&payload)?))
+}
+
+/// Like `Read::read_exact`, but treats a short read as `Ok` instead of
+/// erroring, returning however many bytes were actually available before
+/// EOF so callers can tell a clean stream end from a torn one.
+fn read_to_end_or_limit<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match reader.read(&mut buf[total..])? {
+            0 => break,
+            n => total += n,
+        }
+    }
+    Ok(total)
+}
+
+/// CRC32 (IEEE) of `data`, used to detect torn or bit-rotted frames.
+fn crc32(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -284,6 +1062,7 @@ impl Command {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 struct CommandPos {
     gen: u64,
     pos: u64,
@@ -300,6 +1079,113 @@ impl From<(u64, Range<u64>)> for CommandPos {
     }
 }
 
+/// A segment's backing storage: a real file on disk, or (when
+/// `KvStoreConfig::in_memory` is set) a cursor over a generation's shared
+/// in-memory buffer, so nothing ever touches the filesystem.
+enum SegmentIo {
+    Disk(File),
+    Memory(MemFile),
+}
+
+impl SegmentIo {
+    fn sync_data(&self) -> io::Result<()> {
+        match self {
+            SegmentIo::Disk(file) => file.sync_data(),
+            SegmentIo::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl Read for SegmentIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            SegmentIo::Disk(file) => file.read(buf),
+            SegmentIo::Memory(mem) => mem.read(buf),
+        }
+    }
+}
+
+impl Write for SegmentIo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            SegmentIo::Disk(file) => file.write(buf),
+            SegmentIo::Memory(mem) => mem.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SegmentIo::Disk(file) => file.flush(),
+            SegmentIo::Memory(mem) => mem.flush(),
+        }
+    }
+}
+
+impl Seek for SegmentIo {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            SegmentIo::Disk(file) => file.seek(pos),
+            SegmentIo::Memory(mem) => mem.seek(pos),
+        }
+    }
+}
+
+/// An in-memory segment's cursor: an independent read/write position into
+/// a buffer shared (via `Arc<Mutex<_>>`) with every other cursor opened on
+/// the same generation.
+struct MemFile {
+    data: Arc<Mutex<Vec<u8>>>,
+    pos: u64,
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.lock().unwrap();
+        let start = self.pos as usize;
+        if start >= data.len() {
+            return Ok(0);
+        }
+        let n = (data.len() - start).min(buf.len());
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = self.data.lock().unwrap();
+        let start = self.pos as usize;
+        let end = start + buf.len();
+        if end > data.len() {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.data.lock().unwrap().len() as i64;
+        let new_pos = match pos {
+            SeekFrom::Start(n) => n as i64,
+            SeekFrom::End(n) => len + n,
+            SeekFrom::Current(n) => self.pos as i64 + n,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
 struct BufReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
     pos: u64,
@@ -363,3 +1249,118 @@ impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
         Ok(self.pos)
     }
 }
+
+impl BufWriterWithPos<SegmentIo> {
+    /// `fsync` the active segment's file, trading write throughput for
+    /// durability against a crash. Does not flush the `BufWriter`; callers
+    /// flush first. A no-op for in-memory segments.
+    fn sync_data(&self) -> io::Result<()> {
+        self.writer.get_ref().sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Each test gets its own throwaway directory under the OS temp dir,
+    /// named after the running test and the process id so parallel test
+    /// binaries never collide.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kvs-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn open_recovers_from_a_torn_tail() {
+        let dir = test_dir("torn-tail");
+
+        {
+            let mut store = KvStore::open(&dir).unwrap();
+            for i in 0..5 {
+                store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+            }
+        }
+
+        // simulate a crash mid-write: chop a few bytes off the tail of the
+        // active log, landing inside the last record's frame rather than on
+        // a frame boundary.
+        let log_file = fs::read_dir(&dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .find(|p| p.extension() == Some(OsStr::new("log")))
+            .expect("active log file");
+        let len = fs::metadata(&log_file).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&log_file)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        // `open` should recover by truncating the torn record away, not by
+        // erroring out or losing the records before it.
+        let mut store = KvStore::open(&dir).unwrap();
+        for i in 0..4 {
+            assert_eq!(
+                store.get(format!("key{}", i)).unwrap(),
+                Some(format!("value{}", i))
+            );
+        }
+        assert_eq!(store.get("key4".to_string()).unwrap(), None);
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn get_set_keep_working_while_compaction_runs() {
+        let dir = test_dir("concurrent-compaction");
+
+        let config = KvStoreConfig {
+            in_memory: false,
+            log_config: LogConfig {
+                compaction_threshold: 256,
+                ..Default::default()
+            },
+        };
+        let mut store = KvStore::open_with(&dir, config).unwrap();
+
+        // enough overwrites of the same keys to push `canbe_compacted` past
+        // the threshold and kick off a background compaction.
+        for i in 0..200 {
+            store.set("k".to_string(), format!("v{}", i)).unwrap();
+        }
+
+        // keep hammering get/set while the worker may be mid-rewrite; none
+        // of these should error, and we should actually observe the
+        // compaction in flight rather than finishing before we look.
+        let mut saw_compaction_in_flight = false;
+        for i in 0..2000 {
+            if store.compact_pending.load(Ordering::SeqCst) {
+                saw_compaction_in_flight = true;
+            }
+            store.set(format!("k{}", i % 20), format!("v{}", i)).unwrap();
+            store.get(format!("k{}", i % 20)).unwrap();
+        }
+        assert!(
+            saw_compaction_in_flight,
+            "expected to observe a compaction in flight"
+        );
+
+        while store.compact_pending.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        for i in 0..20 {
+            assert!(store.get(format!("k{}", i)).unwrap().is_some());
+        }
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}